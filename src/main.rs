@@ -2,15 +2,14 @@
 #![plugin(rocket_codegen)]
 #![feature(custom_derive)]
 
-use std::io;
 use std::env;
 use std::collections::HashMap;
 
 extern crate rocket;
-use rocket::request::Form;
-use rocket::response::NamedFile;
+use rocket::{Outcome, Request};
+use rocket::request::{self, Form, FromRequest};
 use rocket::response::Redirect;
-use rocket::http::{Cookie, Cookies};
+use rocket::http::{Cookie, Cookies, Status};
 
 extern crate rocket_contrib;
 use rocket_contrib::Template;
@@ -29,12 +28,17 @@ use dotenv::dotenv;
 extern crate diesel;
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
+use diesel::result::Error::DatabaseError;
+use diesel::result::DatabaseErrorKind;
 #[macro_use]
 extern crate diesel_codegen;
+extern crate diesel_derive_enum;
 
 pub mod schema;
 pub mod models;
 
+use models::Role;
+
 
 fn establish_connection() -> PgConnection {
     dotenv().ok();
@@ -57,10 +61,19 @@ use jwt::{encode, decode, Header, Algorithm};
 
 extern crate argon2rs;
 use argon2rs::verifier::Encoded;
+use argon2rs::{Argon2, Variant};
+
+extern crate rand;
+use rand::Rng;
+
+extern crate base64;
 
 // head -c16 /dev/urandom > secret.key
 static KEY: &'static [u8; 16] = include_bytes!("../secret.key");
-static ONE_WEEK: i64 = 60 * 60 * 24 * 7;
+// Access tokens are short-lived now that lost/stolen cookies can't be
+// revoked any other way; sessions are kept alive via the refresh token below.
+static JWT_EXP_SECONDS: i64 = 60 * 15;
+static REFRESH_TOKEN_EXP_SECONDS: i64 = 60 * 60 * 24 * 30;
 
 
 #[derive(Debug, RustcEncodable, RustcDecodable)]
@@ -70,10 +83,10 @@ struct UserRolesToken {
     // expiration
     exp: i64,
     user: String,
-    roles: Vec<String>,
+    roles: Vec<Role>,
 }
 
-// only has_role() is used in this demo
+// is_claimed_user() is unused in this demo
 impl UserRolesToken {
     fn is_expired(&self) -> bool {
         let now = time::get_time().sec;
@@ -84,17 +97,17 @@ impl UserRolesToken {
         self.user == claimed_user
     }
 
-    fn has_role(&self, role: &str) -> bool {
-        self.roles.contains(&role.to_string())
+    fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
     }
 }
 
 
-fn jwt_generate(user: String, roles: Vec<String>) -> String {
+fn jwt_generate(user: String, roles: Vec<Role>) -> String {
     let now = time::get_time().sec;
     let payload = UserRolesToken {
         iat: now,
-        exp: now + ONE_WEEK,
+        exp: now + JWT_EXP_SECONDS,
         user: user,
         roles: roles,
     };
@@ -104,6 +117,231 @@ fn jwt_generate(user: String, roles: Vec<String>) -> String {
 
 
 
+// AUTHORIZATION GUARDS
+//      Request guards turn "find the jwt cookie, decode it, check a role"
+//      into something Rocket can enforce for us instead of every handler
+//      copy-pasting the same cookie/decode dance.
+//      These only check the JWT's signature and expiry, not `users.blocked`
+//      - an admin block doesn't invalidate an access JWT already issued, it
+//      just stops /refresh from renewing it. See admin_block's comment.
+//
+pub struct AuthenticatedUser(UserRolesToken);
+
+impl AuthenticatedUser {
+    fn username(&self) -> &str {
+        &self.0.user
+    }
+
+    fn has_role(&self, role: Role) -> bool {
+        self.0.has_role(role)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AuthenticatedUser, ()> {
+        let token = match request.cookies().find("jwt").map(|cookie| cookie.value) {
+            Some(jwt) => jwt,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let token_data = match decode::<UserRolesToken>(&token, KEY, Algorithm::HS256) {
+            Ok(data) => data,
+            Err(_) => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        if token_data.claims.is_expired() {
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        Outcome::Success(AuthenticatedUser(token_data.claims))
+    }
+}
+
+pub struct AdminUser(AuthenticatedUser);
+
+impl AdminUser {
+    fn username(&self) -> &str {
+        self.0.username()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AdminUser, ()> {
+        let user = AuthenticatedUser::from_request(request)?;
+
+        if !user.has_role(Role::Admin) {
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+
+        Outcome::Success(AdminUser(user))
+    }
+}
+
+
+
+// CSRF PROTECTION
+//      Double-submit pattern: a random token goes into both a cookie and a
+//      hidden form field when a form is rendered. The CsrfToken guard pulls
+//      the cookie half out of the request; each POST handler then checks it
+//      against the field the form actually submitted and 403s on mismatch.
+//
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    // Constant-time comparison so a timing side-channel can't be used to
+    // recover the token byte-by-byte across repeated requests.
+    fn matches(&self, presented: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let presented = presented.as_bytes();
+
+        if expected.len() != presented.len() {
+            return false;
+        }
+
+        let mismatch = expected.iter()
+            .zip(presented.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+
+        mismatch == 0
+    }
+}
+
+// Shared by POST routes whose form has nothing to submit but the CSRF field itself
+#[derive(FromForm)]
+struct CsrfForm {
+    csrf_token: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CsrfToken, ()> {
+        match request.cookies().find("csrf").map(|cookie| cookie.value) {
+            Some(value) => Outcome::Success(CsrfToken(value)),
+            None => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+// Generates a fresh token, stashes it in the csrf cookie, and returns it so
+// the caller can hand it to a template to embed as a hidden field.
+fn issue_csrf_cookie(cookies: &Cookies) -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+    let token = base64::encode(&bytes);
+
+    cookies.add(Cookie::new("csrf".into(), token.clone()));
+
+    token
+}
+
+
+
+// REFRESH TOKENS
+//      Opaque, random, server-side-tracked tokens so a session can actually
+//      be killed (logout) instead of just waiting out a week-long JWT.
+//      `login` hands one out alongside the access JWT; `refresh` trades a
+//      still-valid one in for a new JWT once the old one expires.
+//
+fn generate_refresh_token(for_user_id: i32, connection: &PgConnection) -> String {
+    use schema::refresh_tokens;
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+    let token_str = base64::encode(&bytes);
+
+    let new_token = models::NewRefreshToken {
+        user_id: for_user_id,
+        token: &token_str,
+        expires_at: time::get_time().sec + REFRESH_TOKEN_EXP_SECONDS,
+    };
+
+    diesel::insert(&new_token)
+        .into(refresh_tokens::table)
+        .execute(connection)
+        .expect("Error saving refresh token");
+
+    token_str
+}
+
+
+
+// REGISTRATION
+//      Lets a visitor create their own row instead of requiring an operator
+//      to INSERT one by hand. Passwords are hashed with Argon2 using a
+//      fresh random salt per user, the same scheme `login` verifies against.
+//
+fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 64];
+    rand::thread_rng().fill(&mut salt);
+
+    let config = Argon2::default(Variant::Argon2i);
+    let encoded = Encoded::new(config, password.as_bytes(), &salt, b"", b"");
+
+    String::from_utf8(encoded.to_u8()).expect("Argon2 hash was not valid UTF-8")
+}
+
+#[derive(FromForm)]
+struct Registration {
+    username: String,
+    password: String,
+    csrf_token: String,
+}
+
+#[post("/register", data="<registration_form>")]
+fn register(csrf: CsrfToken, registration_form: Form<Registration>) -> Result<Redirect, Status> {
+    use schema::users::dsl::*;
+
+    let registration = registration_form.get();
+    if !csrf.matches(&registration.csrf_token) {
+        return Err(Status::Forbidden);
+    }
+
+    let connection = establish_connection();
+
+    let already_taken = users.filter(username.eq(&registration.username))
+        .first::<models::User>(&connection)
+        .is_ok();
+
+    // Check first so the common case sends the visitor back to the form
+    // with a clear reason instead of a silent bounce or a 500.
+    if already_taken {
+        return Ok(Redirect::to("/register?error=taken"));
+    }
+
+    let new_user = models::NewUser {
+        username: &registration.username,
+        pw_hash: hash_password(&registration.password),
+        user_roles: vec![],
+        blocked: false,
+    };
+
+    // The check above is still a check-then-act race, so fall back to the
+    // same error specifically on the `users.username` unique constraint
+    // rejecting the insert - any other failure is a real error and should
+    // surface as a 500 rather than lying to the visitor about why it failed.
+    match diesel::insert(&new_user).into(users).execute(&connection) {
+        Ok(_) => Ok(Redirect::to("/login")),
+        Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Ok(Redirect::to("/register?error=taken"))
+        }
+        Err(e) => panic!("Error saving new user: {:?}", e),
+    }
+}
+
+#[get("/register")]
+fn register_page(cookies: &Cookies) -> Template {
+    let mut context = HashMap::new();
+    context.insert("csrf_token", issue_csrf_cookie(cookies));
+    Template::render("register", &context)
+}
+
+
+
 // AUTHENTICATION
 //      Pretty self-explanatory
 //          - Get row of the user
@@ -116,74 +354,129 @@ fn jwt_generate(user: String, roles: Vec<String>) -> String {
 struct Login {
     username: String,
     password: String,
+    csrf_token: String,
 }
 
 #[post("/login", data="<login_form>")]
-fn login(cookies: &Cookies, login_form: Form<Login>) -> Redirect {
+fn login(cookies: &Cookies, csrf: CsrfToken, login_form: Form<Login>) -> Result<Redirect, Status> {
     use schema::users::dsl::*;
 
     let login = login_form.get();
+    if !csrf.matches(&login.csrf_token) {
+        return Err(Status::Forbidden);
+    }
+
     let connection = establish_connection();
 
     let user = match users.filter(username.eq(&login.username))
         .first::<models::User>(&connection) {
         Ok(u) => u,
-        Err(_) => return Redirect::to("/login"),
+        Err(_) => return Ok(Redirect::to("/login")),
     };
 
-    let hash = user.pw_hash.into_bytes();
+    let hash = user.pw_hash.clone().into_bytes();
 
     // Argon2 password verifier
     let db_hash = Encoded::from_u8(&hash).expect("Failed to read password hash");
     if !db_hash.verify(login.password.as_ref()) {
-        return Redirect::to("/login");
+        return Ok(Redirect::to("/login"));
     }
 
-    // Add JWT to cookies
+    // A blocked account gets no token, regardless of how correct the password is
+    if user.blocked {
+        return Ok(Redirect::to("/login?error=blocked"));
+    }
+
+    // Add JWT and refresh token to cookies
+    let refresh_token = generate_refresh_token(user.id, &connection);
     cookies.add(Cookie::new("jwt".into(), jwt_generate(user.username, user.user_roles)));
+    cookies.add(Cookie::new("refresh_token".into(), refresh_token));
 
-    Redirect::to("/")
+    Ok(Redirect::to("/"))
 }
 
 #[get("/login")]
-fn login_page() -> io::Result<NamedFile> {
-    NamedFile::open("static/login.html")
+fn login_page(cookies: &Cookies) -> Template {
+    let mut context = HashMap::new();
+    context.insert("csrf_token", issue_csrf_cookie(cookies));
+    Template::render("login", &context)
 }
 
-#[post("/logout")]
-fn logout(cookies: &Cookies) -> Redirect {
-    cookies.remove("jwt");
-    Redirect::to("/")
-}
+#[post("/refresh", data="<refresh_form>")]
+fn refresh(cookies: &Cookies, csrf: CsrfToken, refresh_form: Form<CsrfForm>) -> Result<Redirect, Status> {
+    use schema::refresh_tokens::dsl::{refresh_tokens, token};
+    use schema::users::dsl::users;
 
+    if !csrf.matches(&refresh_form.get().csrf_token) {
+        return Err(Status::Forbidden);
+    }
 
+    let presented = match cookies.find("refresh_token").map(|cookie| cookie.value) {
+        Some(t) => t,
+        None => return Ok(Redirect::to("/login")),
+    };
 
-// ADMIN
-//      By using a dynamic path in our main handler, we can use a single block
-//      of cookie-check code to verify if the user has the admin role. Then,
-//      pseudo-redirect the request to another function
-//
-//      By returning 404 instead of 403, we don't reveal that these pages exist
-//      ... also trying to use Result and returning Err(Status) resulted in 500
-//
-#[get("/admin/<path>")]
-fn admin_handler(cookies: &Cookies, path: &str) -> Option<Template> {
-    let token = match cookies.find("jwt").map(|cookie| cookie.value) {
-        Some(jwt) => jwt,
-        _ => return None,
+    let connection = establish_connection();
+
+    let stored = match refresh_tokens.filter(token.eq(&presented))
+        .first::<models::RefreshToken>(&connection) {
+        Ok(t) => t,
+        Err(_) => return Ok(Redirect::to("/login")),
     };
 
-    // You'll want to match on and log errors instead of unwrapping, of course
-    let token_data = decode::<UserRolesToken>(&token, KEY, Algorithm::HS256).unwrap();
+    if stored.expires_at <= time::get_time().sec {
+        return Ok(Redirect::to("/login"));
+    }
+
+    let user = match users.find(stored.user_id).first::<models::User>(&connection) {
+        Ok(u) => u,
+        Err(_) => return Ok(Redirect::to("/login")),
+    };
 
-    if !token_data.claims.has_role("admin") {
-        return None;
+    // A blocked account's refresh token doesn't get to keep minting access JWTs
+    if user.blocked {
+        return Ok(Redirect::to("/login"));
     }
 
+    cookies.add(Cookie::new("jwt".into(), jwt_generate(user.username, user.user_roles)));
+
+    Ok(Redirect::to("/"))
+}
+
+#[post("/logout", data="<logout_form>")]
+fn logout(cookies: &Cookies, csrf: CsrfToken, logout_form: Form<CsrfForm>) -> Result<Redirect, Status> {
+    if !csrf.matches(&logout_form.get().csrf_token) {
+        return Err(Status::Forbidden);
+    }
+
+    // Delete the refresh token server-side so the session is genuinely
+    // killed, not just left to ride out the access JWT's expiry.
+    if let Some(presented) = cookies.find("refresh_token").map(|cookie| cookie.value) {
+        use schema::refresh_tokens::dsl::{refresh_tokens, token};
+        let connection = establish_connection();
+        let _ = diesel::delete(refresh_tokens.filter(token.eq(&presented)))
+            .execute(&connection);
+    }
+
+    cookies.remove("jwt");
+    cookies.remove("refresh_token");
+    Ok(Redirect::to("/"))
+}
+
+
+
+// ADMIN
+//      The dynamic path still dispatches to a sub-handler, but the
+//      authentication/role check itself is now the AdminUser guard -
+//      Rocket returns 401/403 on its own if the guard fails, instead of
+//      us lying with a 404 here.
+//
+#[get("/admin/<path>")]
+fn admin_handler(user: AdminUser, path: &str) -> Option<Template> {
     match path {
-        "index" => return Some(admin_index()),
-        "user" => return Some(display_user(token_data.claims.user)),
-        _ => return None,
+        "index" => Some(admin_index()),
+        "user" => Some(display_user(user.username().to_string())),
+        _ => None,
     }
 }
 
@@ -193,6 +486,41 @@ fn admin_index() -> Template {
     Template::render("admin/index", &context)
 }
 
+// Lets an admin kill a compromised account's access, rather than waiting
+// for the JWT to expire - the stateless week-long token this app started
+// with made that impossible. This revokes the refresh token so no *new*
+// access JWT can be minted, but any access JWT issued before the block
+// still passes AuthenticatedUser/AdminUser (signature + expiry only) until
+// it naturally expires, up to JWT_EXP_SECONDS later.
+#[post("/admin/block/<path_username>", data="<block_form>")]
+fn admin_block(_admin: AdminUser, csrf: CsrfToken, path_username: &str,
+               block_form: Form<CsrfForm>) -> Result<Redirect, Status> {
+    if !csrf.matches(&block_form.get().csrf_token) {
+        return Err(Status::Forbidden);
+    }
+
+    let connection = establish_connection();
+
+    let blocked_user = {
+        use schema::users::dsl::*;
+        diesel::update(users.filter(username.eq(path_username)))
+            .set(blocked.eq(true))
+            .get_result::<models::User>(&connection)
+            .expect("Error blocking user")
+    };
+
+    // Kill any already-issued refresh tokens too, or the block is a no-op
+    // against a session that just keeps renewing its access JWT via /refresh
+    {
+        use schema::refresh_tokens::dsl::*;
+        diesel::delete(refresh_tokens.filter(user_id.eq(blocked_user.id)))
+            .execute(&connection)
+            .expect("Error revoking refresh tokens");
+    }
+
+    Ok(Redirect::to("/admin/index"))
+}
+
 fn display_user(user: String) -> Template {
     use schema::users::dsl::*;
     let connection = establish_connection();
@@ -210,31 +538,27 @@ fn display_user(user: String) -> Template {
 
 
 // LAUNCHER
-//      Index page to redirect user to login, or render their name
+//      Index page renders the user's name, pulled from the AuthenticatedUser
+//      guard; an unauthenticated visitor gets Rocket's default 401 instead
+//      of a hand-rolled redirect.
 //      Start application
 //
 #[get("/")]
-fn index(cookies: &Cookies) -> Result<Template, Redirect> {
-    let token = match cookies.find("jwt").map(|msg| msg.value) {
-        Some(jwt) => jwt,
-        None => return Err(Redirect::to("/login")),
-    };
-
-    let token_data = decode::<UserRolesToken>(&token, KEY, Algorithm::HS256).unwrap();
-
+fn index(user: AuthenticatedUser) -> Template {
     let mut context = HashMap::new();
-    context.insert("name", token_data.claims.user.clone());
+    context.insert("name", user.username().to_string());
 
-    if token_data.claims.has_role("admin") {
+    if user.has_role(Role::Admin) {
         context.insert("admin", "true".to_string());
     }
 
-    Ok(Template::render("index", &context))
+    Template::render("index", &context)
 }
 
 fn main() {
     rocket::ignite()
         .mount("/",
-               routes![index, login, login_page, logout, admin_handler])
+               routes![index, login, login_page, logout, refresh, register, register_page,
+                       admin_handler, admin_block])
         .launch();
 }
@@ -0,0 +1,46 @@
+use diesel_derive_enum::DbEnum;
+
+use schema::{users, refresh_tokens};
+
+// Persisted as a Postgres `role` enum (see migrations/) instead of free-form
+// text, so a typo'd role string can no longer compile and silently never match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DbEnum, RustcEncodable, RustcDecodable)]
+pub enum Role {
+    Admin,
+    User,
+    ZoneAdmin,
+}
+
+#[derive(Queryable)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub pw_hash: String,
+    pub user_roles: Vec<Role>,
+    pub blocked: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub pw_hash: String,
+    pub user_roles: Vec<Role>,
+    pub blocked: bool,
+}
+
+#[derive(Queryable)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token: String,
+    pub expires_at: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+pub struct NewRefreshToken<'a> {
+    pub user_id: i32,
+    pub token: &'a str,
+    pub expires_at: i64,
+}
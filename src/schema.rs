@@ -0,0 +1,30 @@
+// `users.user_roles` is the custom Postgres `role[]` type added in
+// migrations/20170615100000_user_roles_enum. infer_schema! only knows
+// Diesel's builtin OID -> SqlType table, so it can't learn that a `role`
+// column maps to `models::RoleMapping` - this table has to be hand-written.
+// `refresh_tokens` is written out alongside it rather than mixing an
+// infer_schema! call (which infers every table in the schema) with a
+// hand-written `users`, which would just redefine the same module twice.
+table! {
+    use diesel::sql_types::*;
+    use models::RoleMapping;
+
+    users (id) {
+        id -> Integer,
+        username -> Text,
+        pw_hash -> Text,
+        user_roles -> Array<RoleMapping>,
+        blocked -> Bool,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+
+    refresh_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        token -> Text,
+        expires_at -> BigInt,
+    }
+}